@@ -1,21 +1,148 @@
 use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
     io::{self, BufRead},
     sync::mpsc,
-    time::{Duration, Instant},
 };
 
+use serde::Deserialize;
+
 use crate::{
     handler::{Handler, InitializedHandler},
-    messages::Message,
+    messages::{self, Message},
 };
 
-enum Event {
+pub(crate) enum Event {
     Message(String),
     Timer,
 }
 
 const CHANNEL_SIZE: usize = 10;
 
+/// Most events to drain into one batch before handling them and flushing stdout once.
+const MAX_BATCH: usize = 32;
+
+///
+/// How urgently an event should be handled, borrowed from the per-message priority byte
+/// idea in the netapp wire protocol. Variants are listed lowest to highest so the derived
+/// `Ord` lines up with [`BinaryHeap`]'s max-first ordering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Priority {
+    /// Internal anti-entropy work: the gossip timer and inter-node gossip/RBC traffic.
+    Gossip,
+    /// Inbound requests that aren't a client waiting synchronously on a reply.
+    Broadcast,
+    /// Client requests that block on our reply (`read`, `generate`, `echo`).
+    ClientReply,
+}
+
+///
+/// Just enough of a message to read its `body.type` without fully deserializing it.
+#[derive(Deserialize)]
+struct TypePeek {
+    body: TypePeekBody,
+}
+
+#[derive(Deserialize)]
+struct TypePeekBody {
+    #[serde(rename = "type")]
+    message_type: String,
+}
+
+fn priority_of_line(line: &str) -> Priority {
+    let Ok(peek) = serde_json::from_str::<TypePeek>(line) else {
+        return Priority::Gossip;
+    };
+
+    match peek.body.message_type.as_str() {
+        "read" | "generate" | "generate_base58" | "echo" => Priority::ClientReply,
+        "broadcast" | "topology" | "init" => Priority::Broadcast,
+        _ => Priority::Gossip,
+    }
+}
+
+///
+/// An [`Event`] paired with the [`Priority`] it was given on arrival, so a [`BinaryHeap`] of
+/// them drains highest-priority-first.
+struct ScheduledEvent {
+    priority: Priority,
+    event: Event,
+}
+
+impl ScheduledEvent {
+    fn new(event: Event) -> Self {
+        let priority = match &event {
+            Event::Message(line) => priority_of_line(line),
+            Event::Timer => Priority::Gossip,
+        };
+        Self { priority, event }
+    }
+}
+
+impl PartialEq for ScheduledEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority
+    }
+}
+
+impl Eq for ScheduledEvent {}
+
+impl PartialOrd for ScheduledEvent {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScheduledEvent {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority)
+    }
+}
+
+///
+/// A handle that lets code outside the handler loop inject events into it.
+/// Safe to clone and move across threads; `on_init` is the usual way to get one.
+#[derive(Clone)]
+pub(crate) struct Backdoor {
+    tx: mpsc::SyncSender<Event>,
+}
+
+impl Backdoor {
+    ///
+    /// Push a raw, newline-framed message into the handler loop as if it had
+    /// arrived on stdin.
+    #[allow(dead_code)] // not wired up to any on_init caller yet, but kept for future pollers
+    pub(crate) fn send_message(&self, line: String) {
+        if let Err(e) = self.tx.send(Event::Message(line)) {
+            eprintln!("failed to send backdoor message: {e:?}");
+        }
+    }
+
+    ///
+    /// Push a [`Event::Timer`] tick into the handler loop, as if `handle_gossip_timer` had
+    /// fired on its own. Used by [`periodic_gossip_timer`] to drive the timer from an
+    /// `on_init` closure instead of a thread hardcoded into `run_initialized_loop`.
+    pub(crate) fn send_timer(&self) {
+        if let Err(e) = self.tx.send(Event::Timer) {
+            eprintln!("failed to send backdoor timer: {e:?}");
+        }
+    }
+}
+
+///
+/// The default `on_init`: spawns a thread that sends a [`Event::Timer`] tick through the
+/// given [`Backdoor`] every 150ms, driving `handle_gossip_timer`.
+pub(crate) fn periodic_gossip_timer(backdoor: &Backdoor) {
+    let backdoor = backdoor.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        backdoor.send_timer();
+    });
+}
+
+/// The callback `on_init` runs once, with a [`Backdoor`], before the event loop's threads start.
+type OnInit = Box<dyn FnOnce(&Backdoor)>;
+
 pub fn handle_single_line<T: Handler>(line: &str, handler: &mut T) {
     let message: Message = serde_json::from_str(line).unwrap();
 
@@ -27,13 +154,25 @@ pub fn handle_single_line<T: Handler>(line: &str, handler: &mut T) {
 ///
 /// We run 3 threads
 ///     1. Reading from stdin
-///     2. Timer
+///     2. Timer (spawned by `on_init`, e.g. [`periodic_gossip_timer`])
 ///     3. Handler that reacts to both other threads
 ///
 /// We use threads instead of async because I want to learn how to use threads this time :)
-pub fn run_initialized_loop(initialized_handler: InitializedHandler) {
+///
+/// `on_init` is called once with a [`Backdoor`] before any thread starts, so it can spawn
+/// background threads (periodic self-messages, external pollers) that feed the
+/// single-threaded handler safely through the existing channel. Pass `None` to skip the
+/// gossip timer entirely (e.g. in a test harness driving the handler by hand).
+pub fn run_initialized_loop(initialized_handler: InitializedHandler, on_init: Option<OnInit>) {
     let (events_tx, events_rx) = mpsc::sync_channel(CHANNEL_SIZE);
 
+    let backdoor = Backdoor {
+        tx: events_tx.clone(),
+    };
+    if let Some(on_init) = on_init {
+        on_init(&backdoor);
+    }
+
     let stdin_tx = events_tx.clone();
 
     std::thread::spawn(move || {
@@ -46,16 +185,14 @@ pub fn run_initialized_loop(initialized_handler: InitializedHandler) {
         }
     });
 
-    std::thread::spawn(move || loop {
-        std::thread::sleep(std::time::Duration::from_millis(150));
-        if let Err(e) = events_tx.send(Event::Timer) {
-            eprintln!("failed to send new timer event: {e:?}");
-        }
-    });
-
     run_handler_forever(initialized_handler, events_rx);
 }
 
+///
+/// On each wake-up, drain up to `MAX_BATCH` events currently sitting in the channel into a
+/// priority heap and handle them highest-priority-first, so a burst of background gossip
+/// can't delay a latency-sensitive client reply behind it. Outgoing messages from the whole
+/// batch are flushed to stdout once, rather than on every single reply.
 fn run_handler_forever(mut initialized_handler: InitializedHandler, rx: mpsc::Receiver<Event>) {
     loop {
         let event = match rx.recv() {
@@ -66,9 +203,22 @@ fn run_handler_forever(mut initialized_handler: InitializedHandler, rx: mpsc::Re
             }
         };
 
-        match event {
-            Event::Message(line) => handle_single_line(&line, &mut initialized_handler),
-            Event::Timer => initialized_handler.handle_gossip_timer(),
+        let mut scheduled = BinaryHeap::new();
+        scheduled.push(ScheduledEvent::new(event));
+        while scheduled.len() < MAX_BATCH {
+            match rx.try_recv() {
+                Ok(event) => scheduled.push(ScheduledEvent::new(event)),
+                Err(_) => break,
+            }
         }
+
+        while let Some(ScheduledEvent { event, .. }) = scheduled.pop() {
+            match event {
+                Event::Message(line) => handle_single_line(&line, &mut initialized_handler),
+                Event::Timer => initialized_handler.handle_gossip_timer(),
+            }
+        }
+
+        messages::flush_messages();
     }
 }
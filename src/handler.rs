@@ -1,9 +1,13 @@
 use crate::{
     messages::{send_message, Message, MessageBody},
+    reliable_broadcast::{self, Hash, MerkleTree, RbcInstance, RbcShard},
     unique_id::SnowflakeIdGenerator,
 };
 use rand::seq::IteratorRandom;
-use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    time::{Duration, Instant},
+};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -25,26 +29,31 @@ pub trait Handler {
 #[derive(Debug)]
 pub struct UninitHandler {
     machine_id: Option<u16>,
+    total_nodes: usize,
 }
 
 impl UninitHandler {
     pub fn new() -> Self {
-        Self { machine_id: None }
+        Self {
+            machine_id: None,
+            total_nodes: 0,
+        }
     }
 
     pub fn get_initialized_handler(&self) -> Option<InitializedHandler> {
-        self.machine_id.map(InitializedHandler::new)
+        self.machine_id
+            .map(|machine_id| InitializedHandler::new(machine_id, self.total_nodes))
     }
 }
 
 impl Handler for UninitHandler {
     fn handle_message(&mut self, message: Message) -> Result<(), HandlerError> {
-        let (node_id, msg_id) = match message.body {
+        let (node_id, msg_id, total_nodes) = match message.body {
             MessageBody::Init {
                 msg_id,
                 node_id,
-                node_ids: _,
-            } => (node_id, msg_id),
+                node_ids,
+            } => (node_id, msg_id, node_ids.len()),
             _ => return Err(HandlerError::UnprocessableMessage("we can only handle init. should probably add the message type to this error string".to_string())),
         };
 
@@ -56,6 +65,7 @@ impl Handler for UninitHandler {
         }
 
         self.machine_id.replace(machine_id);
+        self.total_nodes = total_nodes;
         let response = Message {
             src: message.dest,
             dest: message.src,
@@ -82,9 +92,17 @@ fn parse_node_id(node_id: String) -> Result<u16, HandlerError> {
         .map_err(|_| HandlerError::InvalidMachineId(node_id))
 }
 
-enum PendingSentMessages {
-    /// An inform broadcast was sent to a neihbor. When he replies with ok we know he got the message.
-    InformBroadcast { messages: HashSet<u32>, dst: String },
+/// A callback fired with the handler and the reply body once a response to an RPC arrives.
+type OnReply = Box<dyn FnOnce(&mut InitializedHandler, MessageBody)>;
+
+///
+/// Bookkeeping for a message we sent that expects a reply. Kept around so
+/// `handle_gossip_timer` can resend it if it times out.
+struct PendingRpc {
+    on_reply: OnReply,
+    message: Message,
+    sent_at: Instant,
+    retries: u32,
 }
 
 ///
@@ -94,6 +112,10 @@ pub struct InitializedHandler {
     unique_id_generator: SnowflakeIdGenerator,
     node_id: String,
     neighbors: Vec<String>,
+    /// Total nodes in the cluster, from `init`'s `node_ids`. Used to guard the reliable
+    /// broadcast path, which only floods echoes one hop and so only reaches every node on
+    /// a complete graph.
+    total_nodes: usize,
 
     current_msg_id: u32,
 
@@ -101,25 +123,118 @@ pub struct InitializedHandler {
     messages: HashSet<u32>,
 
     known_messages_to_neighbors: HashMap<String, HashSet<u32>>,
-    pending_messages_sent: HashMap<u32, PendingSentMessages>,
+    pending_rpcs: HashMap<u32, PendingRpc>,
+
+    /// State for reliable broadcasts in flight, keyed by Merkle root.
+    reliable_broadcasts: HashMap<Hash, RbcInstance>,
 }
 
 impl InitializedHandler {
     const NUM_RANDOM_NEIGHBORS_TO_INFORM: usize = 10;
 
-    pub fn new(machine_id: u16) -> Self {
+    /// How long to wait for a reply before resending an RPC.
+    const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+
+    /// How many times to resend an RPC before giving up on it.
+    const MAX_RPC_RETRIES: u32 = 5;
+
+    /// At this many messages unknown to the neighbors or more, switch from per-neighbor
+    /// gossip to a single erasure-coded reliable broadcast to all of them.
+    const RELIABLE_BROADCAST_THRESHOLD: usize = 64;
+
+    pub fn new(machine_id: u16, total_nodes: usize) -> Self {
         let unique_id_generator = SnowflakeIdGenerator::new(machine_id, 0);
         Self {
             unique_id_generator,
             neighbors: Vec::new(),
+            total_nodes,
             node_id: format!("n{machine_id}"),
             messages: HashSet::new(),
             known_messages_to_neighbors: HashMap::new(),
-            pending_messages_sent: HashMap::new(),
+            pending_rpcs: HashMap::new(),
+            reliable_broadcasts: HashMap::new(),
             current_msg_id: 0,
         }
     }
 
+    ///
+    /// Send `dest` a message and remember `on_reply` so it can be fired once the
+    /// corresponding `in_reply_to` comes back. `make_body` is handed the freshly
+    /// assigned `msg_id` so it can embed it in the outgoing body.
+    fn rpc(
+        &mut self,
+        dest: String,
+        make_body: impl FnOnce(u32) -> MessageBody,
+        on_reply: impl FnOnce(&mut InitializedHandler, MessageBody) + 'static,
+    ) {
+        let msg_id = self.current_msg_id;
+        self.current_msg_id += 1;
+
+        let message = Message {
+            src: self.node_id.clone(),
+            dest,
+            body: make_body(msg_id),
+        };
+        send_message(&message);
+
+        self.pending_rpcs.insert(
+            msg_id,
+            PendingRpc {
+                on_reply: Box::new(on_reply),
+                message,
+                sent_at: Instant::now(),
+                retries: 0,
+            },
+        );
+    }
+
+    ///
+    /// Generic dispatch for any message body carrying `in_reply_to`: look up the
+    /// pending RPC it answers, fire its callback and forget about it.
+    fn handle_rpc_reply(&mut self, in_reply_to: u32, body: MessageBody) {
+        let Some(pending) = self.pending_rpcs.remove(&in_reply_to) else {
+            eprintln!(
+                "Got a reply to a message that wasn't sent (msg_id = {in_reply_to})"
+            );
+            return;
+        };
+
+        (pending.on_reply)(self, body);
+    }
+
+    ///
+    /// Resend any pending RPC that hasn't been replied to within `RPC_TIMEOUT`,
+    /// giving up (and logging) once `MAX_RPC_RETRIES` is exceeded.
+    fn retransmit_pending_rpcs(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<u32> = self
+            .pending_rpcs
+            .iter()
+            .filter(|(_, pending)| now.duration_since(pending.sent_at) >= Self::RPC_TIMEOUT)
+            .map(|(msg_id, _)| *msg_id)
+            .collect();
+
+        for msg_id in expired {
+            let Entry::Occupied(mut entry) = self.pending_rpcs.entry(msg_id) else {
+                continue;
+            };
+
+            let pending = entry.get_mut();
+            if pending.retries >= Self::MAX_RPC_RETRIES {
+                eprintln!(
+                    "giving up on message {msg_id} to {} after {} retries",
+                    pending.message.dest, pending.retries
+                );
+                entry.remove();
+                continue;
+            }
+
+            send_message(&pending.message);
+            pending.retries += 1;
+            pending.sent_at = now;
+        }
+    }
+
     fn handle_topology(&mut self, mut topology: HashMap<String, Vec<String>>) {
         let Some(neighbors) = topology.remove(&self.node_id) else {
             return;
@@ -135,13 +250,57 @@ impl InitializedHandler {
         }
     }
 
+    ///
+    /// Messages that at least one neighbor hasn't acked yet, if there are enough of them
+    /// to be worth shipping as a single reliable broadcast instead of per-neighbor gossip.
+    fn pending_for_reliable_broadcast(&self) -> Option<HashSet<u32>> {
+        // Erasure coding needs at least 2 shards (one per neighbor) to have any
+        // redundancy to offer; with a single neighbor, fall back to plain gossip.
+        if self.neighbors.len() < 2 {
+            return None;
+        }
+
+        // `handle_rbc_val`/`handle_rbc_echo` only flood Echo one hop (to our own
+        // neighbors), so a node more than one hop from the originator can never collect
+        // enough distinct echoes to reconstruct. Only take the RBC path on a complete
+        // graph, where every other node is a direct neighbor; otherwise fall
+        // back to plain gossip, which reaches everyone eventually regardless of topology.
+        if self.neighbors.len() + 1 < self.total_nodes {
+            return None;
+        }
+
+        let pending: HashSet<u32> = self
+            .messages
+            .iter()
+            .copied()
+            .filter(|message| {
+                self.neighbors.iter().any(|neighbor| {
+                    !self
+                        .known_messages_to_neighbors
+                        .get(neighbor)
+                        .is_some_and(|known| known.contains(message))
+                })
+            })
+            .collect();
+
+        (pending.len() >= Self::RELIABLE_BROADCAST_THRESHOLD).then_some(pending)
+    }
+
     fn send_inform_broadcast_to_neighbors(&mut self) {
-        let neighbors_to_inform = self.neighbors.iter().choose_multiple(
-            &mut rand::thread_rng(),
-            Self::NUM_RANDOM_NEIGHBORS_TO_INFORM,
-        );
+        if let Some(pending) = self.pending_for_reliable_broadcast() {
+            self.start_reliable_broadcast(pending);
+            return;
+        }
 
-        for neighbor in neighbors_to_inform.into_iter().cloned() {
+        let neighbors_to_inform: Vec<String> = self
+            .neighbors
+            .iter()
+            .choose_multiple(&mut rand::thread_rng(), Self::NUM_RANDOM_NEIGHBORS_TO_INFORM)
+            .into_iter()
+            .cloned()
+            .collect();
+
+        for neighbor in neighbors_to_inform {
             let known_by_other = self
                 .known_messages_to_neighbors
                 .entry(neighbor.clone())
@@ -153,37 +312,30 @@ impl InitializedHandler {
                 continue;
             }
 
-            let body = MessageBody::InformNewBroadcast {
-                msg_id: self.current_msg_id,
-                messages: messages.clone(),
-            };
-            let message = Message {
-                src: self.node_id.clone(),
-                dest: neighbor.clone(),
-                body,
-            };
-            send_message(&message);
-            match self.pending_messages_sent.entry(self.current_msg_id) {
-                Entry::Occupied(_) => eprintln!(
-                    "pending message with the same message id ({}) was already sent!",
-                    self.current_msg_id
-                ),
-                Entry::Vacant(e) => {
-                    let _ = e.insert(PendingSentMessages::InformBroadcast {
-                        messages,
-                        dst: neighbor,
-                    });
-                }
-            };
-
-            self.current_msg_id += 1;
+            let dst = neighbor.clone();
+            let reply_messages = messages.clone();
+            self.rpc(
+                neighbor,
+                move |msg_id| MessageBody::InformNewBroadcast { msg_id, messages },
+                move |handler, _reply| {
+                    handler
+                        .known_messages_to_neighbors
+                        .entry(dst)
+                        .or_default()
+                        .extend(reply_messages);
+                },
+            );
         }
     }
 
     ///
-    /// Choose a few neighbors in random and send them all the messages we know they haven't seen.
+    /// Choose a few neighbors in random and send them all the messages we know they haven't seen
+    /// (or, once `RELIABLE_BROADCAST_THRESHOLD` of them are pending, start one reliable broadcast
+    /// to every neighbor instead), then resend any previously sent RPC that hasn't been replied
+    /// to in time.
     pub fn handle_gossip_timer(&mut self) {
-        self.send_inform_broadcast_to_neighbors()
+        self.send_inform_broadcast_to_neighbors();
+        self.retransmit_pending_rpcs();
     }
 
     ///
@@ -218,28 +370,196 @@ impl InitializedHandler {
         send_message(&response);
     }
 
-    fn handle_response(&mut self, msg_id: u32) {
-        let Some(pending_message) = self.pending_messages_sent.remove(&msg_id) else {
-            eprintln!("Got an response message to a message that wasn't sent (msg_id = {msg_id})");
+    ///
+    /// Originate an erasure-coded reliable broadcast of `values` to every neighbor: split
+    /// the payload into as many shards as there are neighbors (tolerating up to a third of
+    /// them being lost), build a Merkle tree over the shards and send each neighbor its own
+    /// `RbcVal`. We already know the payload, so we don't need to wait on the protocol to
+    /// add `values` to our own `messages`.
+    fn start_reliable_broadcast(&mut self, values: HashSet<u32>) {
+        let total_shards = self.neighbors.len();
+        if total_shards == 0 {
+            return;
+        }
+
+        let payload = bincode::serialize(&values).expect("HashSet<u32> always serializes");
+
+        let parity_shards = (total_shards / 3).max(1).min(total_shards - 1);
+        let data_shards = total_shards - parity_shards;
+
+        let (shards, payload_len) = reliable_broadcast::encode(&payload, data_shards, parity_shards);
+        let tree = MerkleTree::new(&shards);
+        let root = tree.root();
+
+        for (shard_index, neighbor) in self.neighbors.clone().into_iter().enumerate() {
+            let body = MessageBody::RbcVal {
+                root,
+                data_shards: data_shards as u16,
+                parity_shards: parity_shards as u16,
+                payload_len: payload_len as u32,
+                shard_index: shard_index as u16,
+                shard: shards[shard_index].clone(),
+                branch: tree.branch(shard_index),
+            };
+            send_message(&Message {
+                src: self.node_id.clone(),
+                dest: neighbor,
+                body,
+            });
+        }
+
+        let instance = self
+            .reliable_broadcasts
+            .entry(root)
+            .or_insert_with(|| RbcInstance::new(data_shards, parity_shards, payload_len));
+        instance.origin = true;
+        self.messages.extend(values);
+    }
+
+    ///
+    /// Mark `values` known to every current neighbor. Called once we (the originator of
+    /// this broadcast) have delivered it ourselves: RBC guarantees every correct node
+    /// eventually delivers too, so without this the gossip loop would see these messages
+    /// as still-pending for every neighbor and re-send the whole broadcast forever.
+    fn mark_values_known_to_all_neighbors(&mut self, values: &HashSet<u32>) {
+        for neighbor in self.neighbors.clone() {
+            self.known_messages_to_neighbors
+                .entry(neighbor)
+                .or_default()
+                .extend(values.iter().copied());
+        }
+    }
+
+    ///
+    /// We were sent a shard of someone else's reliable broadcast. Verify it against its
+    /// Merkle branch, then multicast it to all our neighbors as an `Echo` so the network
+    /// converges on who has seen which shard.
+    fn handle_rbc_val(&mut self, sender: String, val: RbcShard) {
+        if !reliable_broadcast::verify_branch(
+            &val.root,
+            &val.shard,
+            val.shard_index as usize,
+            &val.branch,
+        ) {
+            eprintln!("got an RBC Val from {sender} whose shard doesn't match its branch, dropping");
+            return;
+        }
+
+        for neighbor in self.neighbors.clone() {
+            send_message(&Message {
+                src: self.node_id.clone(),
+                dest: neighbor,
+                body: MessageBody::RbcEcho {
+                    root: val.root,
+                    data_shards: val.data_shards,
+                    parity_shards: val.parity_shards,
+                    payload_len: val.payload_len,
+                    shard_index: val.shard_index,
+                    shard: val.shard.clone(),
+                    branch: val.branch.clone(),
+                },
+            });
+        }
+    }
+
+    ///
+    /// Collect echoed shards for a root; once we have `N - f` of them, try to reconstruct
+    /// the payload and re-encode it to confirm it matches the root before sending `Ready`.
+    fn handle_rbc_echo(&mut self, sender: String, echo: RbcShard) {
+        if !reliable_broadcast::verify_branch(
+            &echo.root,
+            &echo.shard,
+            echo.shard_index as usize,
+            &echo.branch,
+        ) {
+            eprintln!("got an RBC Echo from {sender} whose shard doesn't match its branch, dropping");
+            return;
+        }
+
+        let root = echo.root;
+        let instance = self.reliable_broadcasts.entry(root).or_insert_with(|| {
+            RbcInstance::new(
+                echo.data_shards as usize,
+                echo.parity_shards as usize,
+                echo.payload_len as usize,
+            )
+        });
+        instance
+            .echoes
+            .insert(sender, (echo.shard_index as usize, echo.shard));
+
+        if !instance.delivered
+            && !instance.ready_senders.contains(&self.node_id)
+            && instance.try_reconstruct(&root).is_some()
+        {
+            instance.ready_senders.insert(self.node_id.clone());
+            for neighbor in self.neighbors.clone() {
+                send_message(&Message {
+                    src: self.node_id.clone(),
+                    dest: neighbor,
+                    body: MessageBody::RbcReady { root },
+                });
+            }
+        }
+
+        // A new Echo (not just an incoming Ready) might be what completes delivery,
+        // e.g. if enough Readys already arrived before we could reconstruct.
+        self.try_deliver_rbc(root);
+    }
+
+    ///
+    /// Once `2f + 1` nodes have sent `Ready` for a root, try to deliver it.
+    fn handle_rbc_ready(&mut self, sender: String, root: Hash) {
+        let Some(instance) = self.reliable_broadcasts.get_mut(&root) else {
+            eprintln!("got an RBC Ready for a root we don't know about, dropping");
             return;
         };
+        if instance.delivered {
+            return;
+        }
 
-        self.handle_pending_message(pending_message);
+        instance.ready_senders.insert(sender);
+        self.try_deliver_rbc(root);
     }
 
-    fn handle_pending_message(&mut self, message: PendingSentMessages) {
-        match message {
-            PendingSentMessages::InformBroadcast { messages, dst } => self
-                .known_messages_to_neighbors
-                .entry(dst)
-                .and_modify(|known_messages| known_messages.extend(messages))
-                .or_default(),
+    ///
+    /// Deliver `root`'s payload into `self.messages` if we have `2f + 1` Readys and enough
+    /// echoed shards to reconstruct. Called after both new Echoes and new Readys, since
+    /// either one might be what completes the other's threshold first.
+    fn try_deliver_rbc(&mut self, root: Hash) {
+        let Some(instance) = self.reliable_broadcasts.get_mut(&root) else {
+            return;
+        };
+        if instance.delivered || instance.ready_senders.len() < instance.ready_threshold() {
+            return;
+        }
+
+        let Some(payload) = instance.try_reconstruct(&root) else {
+            // not enough echoed shards yet to reconstruct, even though enough Readys arrived
+            return;
         };
+
+        let Ok(values) = bincode::deserialize::<HashSet<u32>>(&payload) else {
+            eprintln!("failed to decode reliable broadcast payload for a root, dropping");
+            return;
+        };
+
+        instance.delivered = true;
+        let origin = instance.origin;
+        self.messages.extend(values.iter().copied());
+        if origin {
+            self.mark_values_known_to_all_neighbors(&values);
+        }
     }
 }
 
 impl Handler for InitializedHandler {
     fn handle_message(&mut self, message: Message) -> Result<(), HandlerError> {
+        if let Some(in_reply_to) = message.body.in_reply_to() {
+            self.handle_rpc_reply(in_reply_to, message.body);
+            return Ok(());
+        }
+
         match message.body {
             MessageBody::Init { .. } => {
                 return Err(HandlerError::UnprocessableMessage(
@@ -272,6 +592,19 @@ impl Handler for InitializedHandler {
                 };
                 send_message(&message);
             }
+            MessageBody::GenerateBase58 { msg_id } => {
+                let id = self.unique_id_generator.generate().to_base58();
+                let body = MessageBody::GenerateBase58Ok {
+                    id,
+                    in_reply_to: msg_id,
+                };
+                let message = Message {
+                    src: message.dest,
+                    dest: message.src,
+                    body,
+                };
+                send_message(&message);
+            }
             MessageBody::Broadcast {
                 msg_id,
                 message: value,
@@ -312,16 +645,60 @@ impl Handler for InitializedHandler {
                 };
                 send_message(&message);
             }
+            MessageBody::InformNewBroadcast { msg_id, messages } => {
+                self.handle_inform_new_broadcast(message.src, msg_id, messages)
+            }
+            MessageBody::RbcVal {
+                root,
+                data_shards,
+                parity_shards,
+                payload_len,
+                shard_index,
+                shard,
+                branch,
+            } => self.handle_rbc_val(
+                message.src,
+                RbcShard {
+                    root,
+                    data_shards,
+                    parity_shards,
+                    payload_len,
+                    shard_index,
+                    shard,
+                    branch,
+                },
+            ),
+            MessageBody::RbcEcho {
+                root,
+                data_shards,
+                parity_shards,
+                payload_len,
+                shard_index,
+                shard,
+                branch,
+            } => self.handle_rbc_echo(
+                message.src,
+                RbcShard {
+                    root,
+                    data_shards,
+                    parity_shards,
+                    payload_len,
+                    shard_index,
+                    shard,
+                    branch,
+                },
+            ),
+            MessageBody::RbcReady { root } => self.handle_rbc_ready(message.src, root),
             MessageBody::InitOk { .. }
             | MessageBody::EchoOk { .. }
             | MessageBody::GenerateOk { .. }
+            | MessageBody::GenerateBase58Ok { .. }
             | MessageBody::BroadcastOk { .. }
             | MessageBody::ReadOk { .. }
-            | MessageBody::TopologyOk { .. } => (),
-            MessageBody::InformNewBroadcast { msg_id, messages } => {
-                self.handle_inform_new_broadcast(message.src, msg_id, messages)
+            | MessageBody::TopologyOk { .. }
+            | MessageBody::InformNewBroadcastOk { .. } => {
+                unreachable!("replies are handled generically via MessageBody::in_reply_to above")
             }
-            MessageBody::InformNewBroadcastOk { in_reply_to } => self.handle_response(in_reply_to),
         };
 
         Ok(())
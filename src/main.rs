@@ -3,21 +3,22 @@ use std::io::{self, BufRead};
 use handler::{Handler, InitializedHandler, UninitHandler};
 use messages::Message;
 
+mod event_loop;
 mod handler;
 mod messages;
+mod reliable_broadcast;
 mod unique_id;
 
 fn main() {
     let initialized_handler = run_uninitialized_loop();
-    let Some(mut initialized_handler) = initialized_handler else {
+    let Some(initialized_handler) = initialized_handler else {
         return;
     };
 
-    let lines = io::stdin().lock().lines();
-    for line in lines {
-        let line = line.unwrap();
-        handle_single_line(&line, &mut initialized_handler);
-    }
+    event_loop::run_initialized_loop(
+        initialized_handler,
+        Some(Box::new(event_loop::periodic_gossip_timer)),
+    );
 }
 
 fn run_uninitialized_loop() -> Option<InitializedHandler> {
@@ -33,6 +34,7 @@ fn run_uninitialized_loop() -> Option<InitializedHandler> {
 
         let line = line.trim();
         handle_single_line(line, &mut handler);
+        messages::flush_messages();
         if let Some(initialized_handler) = handler.get_initialized_handler() {
             break Some(initialized_handler);
         }
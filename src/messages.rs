@@ -1,7 +1,13 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    io::{BufWriter, Stdout, Write},
+};
 
 use serde::{Deserialize, Serialize};
 
+use crate::reliable_broadcast::{Hash, MerkleBranch};
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
@@ -19,6 +25,9 @@ pub enum MessageBody {
     Generate {
         msg_id: u32,
     },
+    GenerateBase58 {
+        msg_id: u32,
+    },
     Broadcast {
         msg_id: u32,
         message: u32,
@@ -44,6 +53,10 @@ pub enum MessageBody {
         id: i64,
         in_reply_to: u32,
     },
+    GenerateBase58Ok {
+        id: String,
+        in_reply_to: u32,
+    },
     BroadcastOk {
         in_reply_to: u32,
     },
@@ -54,6 +67,67 @@ pub enum MessageBody {
     TopologyOk {
         in_reply_to: u32,
     },
+
+    // Inter-node gossip
+    InformNewBroadcast {
+        msg_id: u32,
+        messages: HashSet<u32>,
+    },
+    InformNewBroadcastOk {
+        in_reply_to: u32,
+    },
+
+    // Erasure-coded reliable broadcast (see `reliable_broadcast`)
+    RbcVal {
+        root: Hash,
+        data_shards: u16,
+        parity_shards: u16,
+        payload_len: u32,
+        shard_index: u16,
+        shard: Vec<u8>,
+        branch: MerkleBranch,
+    },
+    RbcEcho {
+        root: Hash,
+        data_shards: u16,
+        parity_shards: u16,
+        payload_len: u32,
+        shard_index: u16,
+        shard: Vec<u8>,
+        branch: MerkleBranch,
+    },
+    RbcReady {
+        root: Hash,
+    },
+}
+
+impl MessageBody {
+    ///
+    /// Returns the `msg_id` this body is a reply to, if it is a reply at all.
+    /// Used to generically dispatch RPC replies without matching on every `*Ok` variant.
+    pub fn in_reply_to(&self) -> Option<u32> {
+        match self {
+            MessageBody::InitOk { in_reply_to }
+            | MessageBody::EchoOk { in_reply_to, .. }
+            | MessageBody::GenerateOk { in_reply_to, .. }
+            | MessageBody::GenerateBase58Ok { in_reply_to, .. }
+            | MessageBody::BroadcastOk { in_reply_to }
+            | MessageBody::ReadOk { in_reply_to, .. }
+            | MessageBody::TopologyOk { in_reply_to }
+            | MessageBody::InformNewBroadcastOk { in_reply_to } => Some(*in_reply_to),
+            MessageBody::Init { .. }
+            | MessageBody::Echo { .. }
+            | MessageBody::Generate { .. }
+            | MessageBody::GenerateBase58 { .. }
+            | MessageBody::Broadcast { .. }
+            | MessageBody::Read { .. }
+            | MessageBody::Topology { .. }
+            | MessageBody::InformNewBroadcast { .. }
+            | MessageBody::RbcVal { .. }
+            | MessageBody::RbcEcho { .. }
+            | MessageBody::RbcReady { .. } => None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -63,8 +137,32 @@ pub struct Message {
     pub body: MessageBody,
 }
 
+thread_local! {
+    /// Buffers outgoing messages so a batch of them shares one stdout lock/flush instead of
+    /// paying for both on every single message.
+    static OUT: RefCell<BufWriter<Stdout>> = RefCell::new(BufWriter::new(std::io::stdout()));
+}
+
+///
+/// Serialize `message` and buffer it, newline-framed as Maelstrom requires. Buffered writes
+/// are only guaranteed to reach the Maelstrom harness once [`flush_messages`] is called
+/// (the `BufWriter` also writes out on its own whenever its buffer fills).
 pub fn send_message(message: &Message) {
-    let stdout = std::io::stdout().lock();
-    serde_json::to_writer(stdout, message)
-        .expect("writing a serialized messaged to stdout shouldn't fail")
+    OUT.with(|out| {
+        let mut out = out.borrow_mut();
+        serde_json::to_writer(&mut *out, message)
+            .expect("writing a serialized messaged to stdout shouldn't fail");
+        out.write_all(b"\n")
+            .expect("writing the trailing newline shouldn't fail");
+    });
+}
+
+///
+/// Flush every message buffered by [`send_message`] so far out to stdout.
+pub fn flush_messages() {
+    OUT.with(|out| {
+        out.borrow_mut()
+            .flush()
+            .expect("flushing stdout shouldn't fail")
+    });
 }
@@ -0,0 +1,247 @@
+///
+/// Erasure-coded reliable broadcast (RBC), modeled on the `hbbft` RBC protocol.
+///
+/// Instead of shipping the full payload to every neighbor, the originator splits it into
+/// `data_shards` pieces and adds `parity_shards` Reed-Solomon parity pieces, so any
+/// `data_shards` of the `data_shards + parity_shards` total are enough to reconstruct it.
+/// A Merkle tree over the shards lets every node verify its shard (and the echoes it
+/// receives from others) against a single 32 byte root, without trusting the sender.
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use sha2::{Digest, Sha256};
+
+pub type Hash = [u8; 32];
+
+fn leaf_hash(shard: &[u8]) -> Hash {
+    Sha256::digest(shard).into()
+}
+
+fn parent_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+///
+/// The sibling hashes needed to recompute the root from a single leaf, bottom to top. One
+/// entry per level, not per sibling: a `None` means that level's node was an odd one out
+/// and got promoted to the next level unchanged, rather than paired with a sibling. Without
+/// an entry for those levels too, `verify_branch` can't tell how many times to halve its
+/// index, and desyncs from `branch` on any shard count that isn't a power of two.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MerkleBranch {
+    siblings: Vec<Option<Hash>>,
+}
+
+///
+/// A Merkle tree built over the shards of one RBC instance. Only the originator keeps
+/// the whole tree; everyone else just verifies branches against the root they were sent.
+pub struct MerkleTree {
+    /// One level per row of the tree, leaves first, root last.
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    pub fn new(shards: &[Vec<u8>]) -> Self {
+        let mut levels = vec![shards.iter().map(|s| leaf_hash(s)).collect::<Vec<_>>()];
+
+        while levels.last().unwrap().len() > 1 {
+            let prev = levels.last().unwrap();
+            let next = prev
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => parent_hash(left, right),
+                    [left] => *left,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+
+        Self { levels }
+    }
+
+    pub fn root(&self) -> Hash {
+        self.levels.last().unwrap()[0]
+    }
+
+    pub fn branch(&self, mut index: usize) -> MerkleBranch {
+        let mut siblings = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling_index = index ^ 1;
+            siblings.push(level.get(sibling_index).copied());
+            index /= 2;
+        }
+        MerkleBranch { siblings }
+    }
+}
+
+///
+/// Recompute the root a shard's branch leads to and check it matches `root`.
+pub fn verify_branch(root: &Hash, shard: &[u8], mut index: usize, branch: &MerkleBranch) -> bool {
+    let mut hash = leaf_hash(shard);
+    for sibling in &branch.siblings {
+        hash = match sibling {
+            Some(sibling) if index.is_multiple_of(2) => parent_hash(&hash, sibling),
+            Some(sibling) => parent_hash(sibling, &hash),
+            // odd one out at this level: promoted to the next level unchanged
+            None => hash,
+        };
+        index /= 2;
+    }
+    &hash == root
+}
+
+///
+/// Split `payload` into `data_shards` equal pieces (padded with trailing zeros) plus
+/// `parity_shards` Reed-Solomon parity pieces, returning the shards and the original
+/// payload length so padding can be stripped again after reconstruction.
+pub fn encode(payload: &[u8], data_shards: usize, parity_shards: usize) -> (Vec<Vec<u8>>, usize) {
+    let shard_len = payload.len().div_ceil(data_shards).max(1);
+
+    let mut shards: Vec<Vec<u8>> = payload
+        .chunks(shard_len)
+        .map(|chunk| {
+            let mut shard = chunk.to_vec();
+            shard.resize(shard_len, 0);
+            shard
+        })
+        .collect();
+    shards.resize(data_shards, vec![0u8; shard_len]);
+    shards.extend((0..parity_shards).map(|_| vec![0u8; shard_len]));
+
+    let encoder = ReedSolomon::new(data_shards, parity_shards)
+        .expect("data_shards and parity_shards are always non-zero");
+    encoder
+        .encode(&mut shards)
+        .expect("shard count and length match what ReedSolomon::new was built with");
+
+    (shards, payload.len())
+}
+
+///
+/// Reconstruct the original payload from a possibly-incomplete set of shards (missing
+/// ones as `None`). Requires at least `data_shards` of the `data_shards + parity_shards`
+/// total to be present.
+pub fn reconstruct(
+    mut shards: Vec<Option<Vec<u8>>>,
+    data_shards: usize,
+    parity_shards: usize,
+    payload_len: usize,
+) -> Option<Vec<u8>> {
+    let decoder = ReedSolomon::new(data_shards, parity_shards).ok()?;
+    decoder.reconstruct(&mut shards).ok()?;
+
+    let mut payload = Vec::with_capacity(payload_len);
+    for shard in shards.into_iter().take(data_shards) {
+        payload.extend(shard.expect("reconstruct fills in every shard on success"));
+    }
+    payload.truncate(payload_len);
+    Some(payload)
+}
+
+///
+/// One shard of an RBC instance plus enough context (shard counts, payload length, its
+/// Merkle branch) for the receiver to verify and forward it. Bundles what would otherwise
+/// be the near-identical argument lists of `RbcVal` and `RbcEcho`'s handlers.
+pub struct RbcShard {
+    pub root: Hash,
+    pub data_shards: u16,
+    pub parity_shards: u16,
+    pub payload_len: u32,
+    pub shard_index: u16,
+    pub shard: Vec<u8>,
+    pub branch: MerkleBranch,
+}
+
+///
+/// Per-root state a node tracks while an RBC instance is in flight: the shards echoed to
+/// us so far (enough of which let us reconstruct and re-derive the root) and who has sent
+/// `Ready` for it (enough of which let us deliver).
+pub struct RbcInstance {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    pub payload_len: usize,
+    pub echoes: std::collections::HashMap<String, (usize, Vec<u8>)>,
+    pub ready_senders: std::collections::HashSet<String>,
+    pub delivered: bool,
+    /// Whether this node originated the broadcast this instance tracks. Set so the
+    /// originator can tell, once delivery completes, that it's safe to mark every
+    /// neighbor as having the broadcast values (RBC guarantees eventual delivery to
+    /// every correct node once it has delivered itself).
+    pub origin: bool,
+}
+
+impl RbcInstance {
+    pub fn new(data_shards: usize, parity_shards: usize, payload_len: usize) -> Self {
+        Self {
+            data_shards,
+            parity_shards,
+            payload_len,
+            echoes: std::collections::HashMap::new(),
+            ready_senders: std::collections::HashSet::new(),
+            delivered: false,
+            origin: false,
+        }
+    }
+
+    /// `N - f`: the number of echoes needed before we can attempt reconstruction.
+    pub fn echo_threshold(&self) -> usize {
+        self.data_shards
+    }
+
+    /// `2f + 1`: the number of `Ready`s needed before we can deliver.
+    pub fn ready_threshold(&self) -> usize {
+        2 * self.parity_shards + 1
+    }
+
+    /// Try to reconstruct the payload from the echoes collected so far, returning it only
+    /// if re-encoding it reproduces `root` (guarding against a faulty/malicious sender).
+    pub fn try_reconstruct(&self, root: &Hash) -> Option<Vec<u8>> {
+        if self.echoes.len() < self.echo_threshold() {
+            return None;
+        }
+
+        let total_shards = self.data_shards + self.parity_shards;
+        let mut shards: Vec<Option<Vec<u8>>> = vec![None; total_shards];
+        for (index, shard) in self.echoes.values() {
+            shards[*index] = Some(shard.clone());
+        }
+
+        let payload = reconstruct(
+            shards,
+            self.data_shards,
+            self.parity_shards,
+            self.payload_len,
+        )?;
+
+        let (re_encoded, _) = encode(&payload, self.data_shards, self.parity_shards);
+        if MerkleTree::new(&re_encoded).root() != *root {
+            return None;
+        }
+
+        Some(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_branch_verifies_for_every_shard_count_and_index() {
+        for n in 1..=9 {
+            let shards: Vec<Vec<u8>> = (0..n).map(|i| vec![i as u8]).collect();
+            let tree = MerkleTree::new(&shards);
+            let root = tree.root();
+
+            for (index, shard) in shards.iter().enumerate() {
+                let branch = tree.branch(index);
+                assert!(
+                    verify_branch(&root, shard, index, &branch),
+                    "n = {n}, index = {index} failed to verify"
+                );
+            }
+        }
+    }
+}
@@ -1,4 +1,7 @@
-use chrono::{DateTime, TimeZone, Utc};
+use std::cmp::Ordering;
+
+use chrono::{DateTime, Utc};
+use thiserror::Error;
 
 ///
 /// Implementation for the snowflake ID. See: https://en.wikipedia.org/wiki/Snowflake_ID
@@ -48,26 +51,104 @@ impl SnowflakeId {
         // we know the number of bits is 12 so it fits in a u16
         sequence as u16
     }
+
+    ///
+    /// Render the ID as a short base58 token instead of a 19-digit decimal number, handy
+    /// for embedding in URLs or showing to humans. Base58 (rather than base62) skips the
+    /// `0`/`O`/`I`/`l` characters that are easy to misread.
+    pub fn to_base58(&self) -> String {
+        let mut value = self.0 as u64;
+        if value == 0 {
+            return (BASE58_ALPHABET[0] as char).to_string();
+        }
+
+        let mut digits = Vec::new();
+        while value > 0 {
+            digits.push(BASE58_ALPHABET[(value % 58) as usize]);
+            value /= 58;
+        }
+        digits.reverse();
+
+        String::from_utf8(digits).expect("base58 alphabet is all ASCII")
+    }
+
+    ///
+    /// Parse a token produced by [`SnowflakeId::to_base58`] back into an ID. Not called from
+    /// the handler yet (nothing decodes an ID we've handed out), but round-tripped by tests.
+    #[allow(dead_code)]
+    pub fn from_base58(encoded: &str) -> Result<Self, Base58DecodeError> {
+        let mut value: u64 = 0;
+        for c in encoded.chars() {
+            let digit = BASE58_ALPHABET
+                .iter()
+                .position(|&b| b as char == c)
+                .ok_or(Base58DecodeError::InvalidCharacter(c))?;
+            value = value.wrapping_mul(58).wrapping_add(digit as u64);
+        }
+
+        Ok(Self(value as i64))
+    }
+}
+
+/// Bitcoin-style base58 alphabet: digits and letters, minus `0`, `O`, `I` and `l`.
+const BASE58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+#[allow(dead_code)] // only constructed by `from_base58`, see its doc comment
+#[derive(Debug, Error)]
+pub enum Base58DecodeError {
+    #[error("'{0}' is not a valid base58 character")]
+    InvalidCharacter(char),
 }
 
 pub struct SnowflakeIdGenerator {
     machine_id: u16,
     sequence: u16,
+    /// The millisecond timestamp the last generated ID used, so we know whether to keep
+    /// incrementing `sequence` or reset it for a new millisecond.
+    last_timestamp_ms: i64,
 }
 
 impl SnowflakeIdGenerator {
+    /// The highest value `sequence` can hold given `SnowflakeId::SEQUENCE_BITS`.
+    const MAX_SEQUENCE: u16 = (1 << SnowflakeId::SEQUENCE_BITS) - 1;
+
     pub fn new(machine_id: u16, sequence: u16) -> Self {
         Self {
             machine_id,
             sequence,
+            last_timestamp_ms: i64::MIN,
         }
     }
 
+    ///
+    /// Generate the next ID. Within the same millisecond this increments `sequence`,
+    /// busy-waiting for the next millisecond once its 12 bits are exhausted. Moving to a
+    /// new millisecond resets `sequence` to 0. If the wall clock moves backward (e.g. an
+    /// NTP step-back), this busy-waits for it to catch back up rather than risking a
+    /// duplicate or out-of-order ID — returning an error here would just leave the client
+    /// that asked for an ID hanging, since nothing downstream replies to a dropped request.
     pub fn generate(&mut self) -> SnowflakeId {
-        let timestamp = Utc::now();
-        let id = SnowflakeId::new(self.machine_id, timestamp, self.sequence);
-        self.sequence += 1;
-        id
+        loop {
+            let timestamp = Utc::now();
+            let now_ms = timestamp.timestamp_millis();
+
+            match now_ms.cmp(&self.last_timestamp_ms) {
+                Ordering::Less => continue,
+                Ordering::Equal => {
+                    if self.sequence == Self::MAX_SEQUENCE {
+                        // this millisecond's sequence space is exhausted; spin until the clock ticks over
+                        continue;
+                    }
+                    self.sequence += 1;
+                }
+                Ordering::Greater => {
+                    self.last_timestamp_ms = now_ms;
+                    self.sequence = 0;
+                }
+            }
+
+            return SnowflakeId::new(self.machine_id, timestamp, self.sequence);
+        }
     }
 }
 
@@ -89,4 +170,38 @@ mod tests {
         let id = SnowflakeId::new(378, timestamp, 0);
         assert_eq!(id.get(), 1541815603606036480);
     }
+
+    #[test]
+    fn test_generate_same_millisecond_increments_sequence() {
+        let mut generator = SnowflakeIdGenerator::new(1, 0);
+        generator.last_timestamp_ms = Utc::now().timestamp_millis();
+
+        let first = generator.generate();
+        assert_eq!(first.sequence(), 1);
+        let second = generator.generate();
+        assert_eq!(second.sequence(), 2);
+    }
+
+    #[test]
+    fn test_generate_blocks_until_clock_catches_up_on_regression() {
+        let mut generator = SnowflakeIdGenerator::new(1, 0);
+        let regressed_to = Utc::now().timestamp_millis() + 5;
+        generator.last_timestamp_ms = regressed_to;
+
+        let id = generator.generate();
+        assert!(id.timestamp().timestamp_millis() >= regressed_to);
+    }
+
+    #[test]
+    fn test_base58_round_trip() {
+        let id = SnowflakeId(1541815603606036480);
+        let encoded = id.to_base58();
+        let decoded = SnowflakeId::from_base58(&encoded).unwrap();
+        assert_eq!(decoded.get(), id.get());
+    }
+
+    #[test]
+    fn test_base58_rejects_ambiguous_characters() {
+        assert!(SnowflakeId::from_base58("0OIl").is_err());
+    }
 }